@@ -3,59 +3,339 @@ Purpose of this crate - URL normalization [WHATWG RFC] (https://tools.ietf.org/h
 */
 extern crate url;
 use url::Url;
-use std::collections::BinaryHeap;
-use std::cmp::Ord;
+use url::Host;
+use std::net::IpAddr;
 use std::cmp::Ordering;
 
-#[derive(PartialEq,Eq)]
-struct Pair {
-	key: String,
-	value: String,
+/// Error returned by the normalization pipeline.
+#[derive(Debug,PartialEq,Eq)]
+pub enum NormalizeError {
+	/// `Url::set_scheme` rejected a downgrade to the given scheme.
+	SchemeChange(String),
 }
 
-impl PartialOrd for Pair {
-	fn partial_cmp(&self, other: &Pair) -> Option<Ordering> {
-		return Some(cmp_string(&self.key, &other.key));
+/// Configurable normalization pipeline.
+///
+/// Each field toggles one step; construct a non-default configuration with
+/// struct-update syntax, e.g. `Normalizer { remove_fragment: false, ..Normalizer::default() }`.
+pub struct Normalizer {
+	/// Sort the query pairs.
+	pub sort_query: bool,
+	/// Drop the fragment.
+	pub remove_fragment: bool,
+	/// Downgrade encrypted schemes (`https` → `http`, …).
+	pub downgrade_scheme: bool,
+	/// Strip a port equal to the scheme default.
+	pub strip_default_port: bool,
+	/// Apply the remove_dot_segments path algorithm.
+	pub remove_dot_segments: bool,
+	/// Case-fold and canonicalize the host.
+	pub lowercase_host: bool,
+	/// Decode percent-escaped unreserved octets.
+	pub decode_unreserved: bool,
+	/// Drop the query entirely when it ends up empty.
+	pub remove_empty_query: bool,
+	/// Remove well-known analytics parameters (`utm_*`, `gclid`, …).
+	pub remove_tracking_params: bool,
+	/// Additional parameter keys to strip from the query.
+	pub deny_params: Vec<String>,
+	/// Remove parameters whose value is empty.
+	pub remove_empty_params: bool,
+}
+
+/// Well-known analytics parameters stripped by `remove_tracking_params`.
+const TRACKING_PARAMS: &[&str] = &[
+	"utm_source",
+	"utm_medium",
+	"utm_campaign",
+	"utm_term",
+	"utm_content",
+	"gclid",
+	"fbclid",
+	"mc_eid",
+];
+
+impl Default for Normalizer {
+	fn default() -> Self {
+		return Normalizer {
+			sort_query: true,
+			remove_fragment: true,
+			downgrade_scheme: true,
+			strip_default_port: true,
+			remove_dot_segments: true,
+			lowercase_host: true,
+			decode_unreserved: true,
+			remove_empty_query: false,
+			remove_tracking_params: false,
+			deny_params: Vec::new(),
+			remove_empty_params: false,
+		};
+	}
+}
+
+impl Normalizer {
+	/// Runs the enabled steps over `url` in canonical order.
+	pub fn apply(&self, url: Url) -> Result<Url,NormalizeError> {
+		let mut url = url;
+		if self.decode_unreserved {
+			url = normalize_percent_encoding(url);
+		}
+		if self.remove_dot_segments {
+			url = normalize_path(url);
+		}
+		if self.lowercase_host {
+			url = normalize_host(url);
+		}
+		if self.remove_tracking_params || self.remove_empty_params || !self.deny_params.is_empty() {
+			url = filter_query(url, self.remove_tracking_params, &self.deny_params, self.remove_empty_params);
+		}
+		if self.sort_query {
+			url = normalize_query(url);
+		}
+		if self.remove_empty_query {
+			url = remove_empty_query(url);
+		}
+		if self.remove_fragment {
+			url = normalize_hash(url);
+		}
+		if self.downgrade_scheme {
+			url = normalize_scheme(url)?;
+		}
+		if self.strip_default_port {
+			url = normalize_port(url);
+		}
+		return Ok(url);
+	}
+}
+
+/// Drops the query string when it is present but empty so no dangling `?` is left.
+fn remove_empty_query(mut url: Url) -> Url {
+	if let Some("") = url.query() {
+		url.set_query(None);
 	}
+	return url;
+}
+
+/// Normalizes URL using the default configuration.
+pub fn normalize(url: Url) -> Result<Url,NormalizeError> {
+	return Normalizer::default().apply(url);
 }
 
-impl Ord for Pair {
-	fn cmp(&self, other: &Self) -> Ordering {
-		return cmp_string(&self.key, &other.key);
+/// Normalizes percent-encoded octets in the path, query and fragment (RFC 3986 §6.2.2.2).
+///
+/// Percent-escapes whose byte is an unreserved character are decoded to their
+/// literal form, every other escape keeps its two hex digits uppercased.
+pub fn normalize_percent_encoding(mut url: Url) -> Url {
+	let path = normalize_percent_component(url.path());
+	url.set_path(&path);
+	if let Some(query) = url.query() {
+		let query = normalize_percent_component(query);
+		url.set_query(Some(&query));
+	}
+	if let Some(fragment) = url.fragment() {
+		let fragment = normalize_percent_component(fragment);
+		url.set_fragment(Some(&fragment));
 	}
+	return url;
 }
 
-fn cmp_string(a:&String, b: &String) -> Ordering {
-	let result = a.len().cmp(&b.len());
-	match result {
-		Ordering::Less => Ordering::Less,
-		Ordering::Greater => Ordering::Greater,
-		Ordering::Equal => {
-			return a.cmp(b);
+/// Scans a single URL component byte-by-byte applying §6.2.2.2 normalization.
+fn normalize_percent_component(input: &str) -> String {
+	let bytes = input.as_bytes();
+	let mut output = String::with_capacity(bytes.len());
+	let mut index = 0;
+	while index < bytes.len() {
+		let byte = bytes[index];
+		if byte == b'%' && index + 2 < bytes.len() {
+			if let (Some(high), Some(low)) = (hex_value(bytes[index + 1]), hex_value(bytes[index + 2])) {
+				let decoded = (high << 4) | low;
+				if is_unreserved(decoded) {
+					output.push(decoded as char);
+				} else {
+					output.push('%');
+					output.push(to_upper_hex(high));
+					output.push(to_upper_hex(low));
+				}
+				index += 3;
+				continue;
+			}
 		}
+		output.push(byte as char);
+		index += 1;
+	}
+	return output;
+}
+
+/// Parses a single ASCII hex digit.
+fn hex_value(byte: u8) -> Option<u8> {
+	match byte {
+		b'0'..=b'9' => Some(byte - b'0'),
+		b'a'..=b'f' => Some(byte - b'a' + 10),
+		b'A'..=b'F' => Some(byte - b'A' + 10),
+		_ => None,
 	}
 }
 
-/// Normalizes URL
-pub fn normalize(url: Url) -> Result<Url,()> {
-	let url = normalize_query(url);
-	let url = normalize_hash(url);
-	return normalize_scheme(url);
+/// Renders a nibble as an uppercase hex digit.
+fn to_upper_hex(value: u8) -> char {
+	return b"0123456789ABCDEF"[value as usize] as char;
+}
+
+/// Tests membership of the RFC 3986 unreserved set.
+fn is_unreserved(byte: u8) -> bool {
+	match byte {
+		b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => true,
+		b'-' | b'.' | b'_' | b'~' => true,
+		_ => false,
+	}
 }
 
-/// Sorts url query in alphabet order.
+/// Sorts the query pairs by the byte-wise order of their percent-decoded keys.
+///
+/// Ties on the key are broken by the value; the sort is stable so equal pairs
+/// keep their original relative order.
 pub fn normalize_query(mut url: Url) -> Url {
-	let query_pairs: BinaryHeap<Pair> = url.query_pairs().into_owned().map(
-		|(key,value)| {
-			Pair {
-				key: key,
-				value: value,
+	if url.query().is_none() {
+		return url;
+	}
+	let mut pairs: Vec<(String,String)> = url.query_pairs().into_owned().collect();
+	pairs.sort_by(|a, b| {
+		match a.0.as_bytes().cmp(b.0.as_bytes()) {
+			Ordering::Equal => a.1.as_bytes().cmp(b.1.as_bytes()),
+			other => other,
+		}
+	});
+	url.query_pairs_mut().clear();
+	for &(ref key, ref value) in &pairs {
+		url.query_pairs_mut().append_pair(key, value);
+	}
+	return url;
+}
+
+/// Removes `.`/`..` segments and collapses repeated slashes (RFC 3986 §5.2.4).
+pub fn normalize_path(mut url: Url) -> Url {
+	let had_trailing_slash = url.path().len() > 1 && url.path().ends_with('/');
+	let mut input = url.path().to_string();
+	let mut output = String::new();
+	while !input.is_empty() {
+		if input.starts_with("../") {
+			input.replace_range(0..3, "");
+		} else if input.starts_with("./") {
+			input.replace_range(0..2, "");
+		} else if input.starts_with("/./") {
+			input.replace_range(0..3, "/");
+		} else if input == "/." {
+			input = "/".to_string();
+		} else if input.starts_with("/../") {
+			input.replace_range(0..4, "/");
+			pop_last_segment(&mut output);
+		} else if input == "/.." {
+			input = "/".to_string();
+			pop_last_segment(&mut output);
+		} else if input == "." || input == ".." {
+			input.clear();
+		} else {
+			let start = if input.starts_with('/') { 1 } else { 0 };
+			let end = match input[start..].find('/') {
+				Some(position) => start + position,
+				None => input.len(),
+			};
+			output.push_str(&input[..end]);
+			input.replace_range(0..end, "");
+		}
+	}
+	let mut output = collapse_slashes(&output);
+	if had_trailing_slash && !output.ends_with('/') {
+		output.push('/');
+	}
+	url.set_path(&output);
+	return url;
+}
+
+/// Removes the last written path segment back to and including the previous slash.
+fn pop_last_segment(output: &mut String) {
+	match output.rfind('/') {
+		Some(position) => output.truncate(position),
+		None => output.clear(),
+	}
+}
+
+/// Collapses runs of `/` into a single slash without popping past the root.
+fn collapse_slashes(path: &str) -> String {
+	let mut output = String::with_capacity(path.len());
+	let mut previous_slash = false;
+	for character in path.chars() {
+		if character == '/' {
+			if !previous_slash {
+				output.push('/');
+			}
+			previous_slash = true;
+		} else {
+			output.push(character);
+			previous_slash = false;
+		}
+	}
+	return output;
+}
+
+/// Case-folds the host and canonicalizes it through the `url` crate's `Host`.
+///
+/// Domains are lowercased, stripped of a single trailing dot and run through
+/// IDNA to a single ASCII/punycode form; IP literals are re-emitted in their
+/// canonical dotted-decimal or compressed bracketed form.
+pub fn normalize_host(mut url: Url) -> Url {
+	let host = match url.host() {
+		Some(host) => host.to_owned(),
+		None => return url,
+	};
+	match host {
+		Host::Domain(domain) => {
+			let domain = if domain.ends_with('.') { &domain[..domain.len() - 1] } else { &domain[..] };
+			let domain = domain.to_lowercase();
+			if let Ok(canonical) = Host::parse(&domain) {
+				url.set_host(Some(&canonical.to_string())).ok();
+			}
+		},
+		Host::Ipv4(address) => {
+			url.set_ip_host(IpAddr::V4(address)).ok();
+		},
+		Host::Ipv6(address) => {
+			url.set_ip_host(IpAddr::V6(address)).ok();
+		},
+	}
+	return url;
+}
+
+/// Removes tracking and/or empty-valued parameters from the query.
+///
+/// Rebuilds the query from `url.query_pairs()` dropping any key in the built-in
+/// tracking set (when `remove_tracking` is set), any key in `deny_list`, and
+/// (when `remove_empty` is set) any pair with an empty value. A query that ends
+/// up empty is cleared so no dangling `?` is left.
+pub fn filter_query(mut url: Url, remove_tracking: bool, deny_list: &[String], remove_empty: bool) -> Url {
+	if url.query().is_none() {
+		return url;
+	}
+	let pairs: Vec<(String,String)> = url.query_pairs().into_owned().filter(
+		|&(ref key, ref value)| {
+			if remove_tracking && TRACKING_PARAMS.contains(&key.as_str()) {
+				return false;
+			}
+			if deny_list.iter().any(|denied| denied == key) {
+				return false;
+			}
+			if remove_empty && value.is_empty() {
+				return false;
 			}
+			return true;
 		}
 	).collect();
 	url.query_pairs_mut().clear();
-	for pair in query_pairs.iter().rev() {
-		url.query_pairs_mut().append_pair(&pair.key, &pair.value);
+	for &(ref key, ref value) in &pairs {
+		url.query_pairs_mut().append_pair(key, value);
+	}
+	if let Some("") = url.query() {
+		url.set_query(None);
 	}
 	return url;
 }
@@ -67,7 +347,7 @@ pub fn normalize_hash(mut url: Url) -> Url {
 }
 
 /// Changes encrypted scheme to unencrypted
-pub fn normalize_scheme(mut url: Url) -> Result<Url,()> {
+pub fn normalize_scheme(mut url: Url) -> Result<Url,NormalizeError> {
 	let new_scheme;
 	{
 		let scheme = url.scheme();
@@ -80,11 +360,31 @@ pub fn normalize_scheme(mut url: Url) -> Result<Url,()> {
 		};
 	}
 	if let Some(scheme) = new_scheme {
-		url.set_scheme(scheme)?;
+		if url.set_scheme(scheme).is_err() {
+			return Err(NormalizeError::SchemeChange(scheme.to_string()));
+		}
 	}
 	return Ok(url);
 }
 
+/// Removes an explicit port when it matches the scheme's default.
+///
+/// Must run after `normalize_scheme` so the check sees the final scheme.
+pub fn normalize_port(mut url: Url) -> Url {
+	let default = match url.scheme() {
+		"http" | "ws" => Some(80),
+		"https" | "wss" => Some(443),
+		"ftp" => Some(21),
+		_ => None,
+	};
+	if let (Some(port), Some(default)) = (url.port(), default) {
+		if port == default {
+			url.set_port(None).ok();
+		}
+	}
+	return url;
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -92,7 +392,79 @@ mod tests {
     fn test_normalize_query() {
         let url = Url::parse("https://example.com?c=1&q[]=99&q[5]=44&b=2&a=3#hash").unwrap();
         let url = normalize_query(url);
-        assert_eq!(url.as_str(), "https://example.com/?a=3&b=2&c=1&q%5B%5D=99&q%5B5%5D=44#hash");
+        assert_eq!(url.as_str(), "https://example.com/?a=3&b=2&c=1&q%5B5%5D=44&q%5B%5D=99#hash");
+    }
+
+    #[test]
+    fn test_normalize_query_duplicate_keys() {
+        let url = Url::parse("https://example.com/?b=1&a=2&a=1").unwrap();
+        let url = normalize_query(url);
+        assert_eq!(url.as_str(), "https://example.com/?a=1&a=2&b=1");
+    }
+
+    #[test]
+    fn test_normalize_query_prefix_keys() {
+        let url = Url::parse("https://example.com/?ab=1&a=2").unwrap();
+        let url = normalize_query(url);
+        assert_eq!(url.as_str(), "https://example.com/?a=2&ab=1");
+    }
+
+    #[test]
+    fn test_normalize_percent_encoding() {
+        let url = Url::parse("https://example.com/%7efoo%2Fbar?x=%7E%2f#%7e").unwrap();
+        let url = normalize_percent_encoding(url);
+        assert_eq!(url.as_str(), "https://example.com/~foo%2Fbar?x=~%2F#~");
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let url = Url::parse("https://example.com/a/b/../c").unwrap();
+        assert_eq!(normalize_path(url).as_str(), "https://example.com/a/c");
+        let url = Url::parse("https://example.com/a/./b/").unwrap();
+        assert_eq!(normalize_path(url).as_str(), "https://example.com/a/b/");
+        let url = Url::parse("https://example.com/a/b/../../").unwrap();
+        assert_eq!(normalize_path(url).as_str(), "https://example.com/");
+        let url = Url::parse("https://example.com/a//b").unwrap();
+        assert_eq!(normalize_path(url).as_str(), "https://example.com/a/b");
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        let url = Url::parse("https://EXAMPLE.COM./path").unwrap();
+        assert_eq!(normalize_host(url).as_str(), "https://example.com/path");
+        let url = Url::parse("https://[2001:db8:0:0:0:0:0:1]/").unwrap();
+        assert_eq!(normalize_host(url).as_str(), "https://[2001:db8::1]/");
+    }
+
+    #[test]
+    fn test_normalizer_keeps_fragment() {
+        let normalizer = Normalizer { remove_fragment: false, downgrade_scheme: false, ..Normalizer::default() };
+        let url = Url::parse("https://example.com/?b=2&a=1#hash").unwrap();
+        let url = normalizer.apply(url).unwrap();
+        assert_eq!(url.as_str(), "https://example.com/?a=1&b=2#hash");
+    }
+
+    #[test]
+    fn test_filter_query() {
+        let url = Url::parse("https://example.com/?utm_source=news&id=5&empty=&x=1").unwrap();
+        let deny = vec!["x".to_string()];
+        let url = filter_query(url, true, &deny, true);
+        assert_eq!(url.as_str(), "https://example.com/?id=5");
+    }
+
+    #[test]
+    fn test_filter_query_clears_dangling() {
+        let url = Url::parse("https://example.com/?gclid=abc").unwrap();
+        let url = filter_query(url, true, &[], false);
+        assert_eq!(url.as_str(), "https://example.com/");
+    }
+
+    #[test]
+    fn test_normalize_port() {
+        let url = Url::parse("http://example.com:80/").unwrap();
+        assert_eq!(normalize_port(url).as_str(), "http://example.com/");
+        let url = Url::parse("http://example.com:8080/").unwrap();
+        assert_eq!(normalize_port(url).as_str(), "http://example.com:8080/");
     }
 
     #[test]